@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use toml;
+
+use detect::{DetectFn, DetectResult, Protocol};
+
+
+/// The on-disk (TOML) representation of demuxrs's configuration. This is
+/// deliberately a plain data struct decoupled from the runtime `Config` in
+/// `main.rs`: the runtime struct merges this in alongside whatever was
+/// passed on the command line.
+#[derive(Debug, Deserialize)]
+pub struct FileConfig {
+    /// Additional listen addresses, in host:port form. These supplement
+    /// (rather than replace) the `--listen` CLI flag.
+    #[serde(default)]
+    pub listen: Vec<String>,
+
+    /// Overrides the detection timeout (in milliseconds), if set.
+    pub timeout: Option<i64>,
+
+    /// Overrides the idle timeout (in milliseconds) for established
+    /// proxied connections, if set.
+    pub idle_timeout: Option<i64>,
+
+    /// Overrides the maximum number of simultaneous connections before
+    /// accepts are throttled, if set.
+    pub max_connections: Option<usize>,
+
+    /// Per-protocol upstream addresses, keyed by protocol name. This can
+    /// name either a builtin protocol or one declared in `signatures`.
+    #[serde(default)]
+    pub upstreams: HashMap<String, String>,
+
+    /// SNI-qualified TLS upstreams, keyed by hostname; the CLI equivalent
+    /// of `--tls-sni-upstream hostname=host:port`. Consulted before the
+    /// generic `tls` entry in `upstreams`.
+    #[serde(default)]
+    pub sni_upstreams: HashMap<String, String>,
+
+    /// User-defined protocol signatures, appended to the builtin protocol
+    /// table at startup.
+    #[serde(default)]
+    pub signatures: Vec<SignatureConfig>,
+}
+
+/// A single user-defined protocol signature.
+#[derive(Debug, Deserialize)]
+pub struct SignatureConfig {
+    pub name: String,
+
+    /// The detector reports `NotEnoughData` until the buffer reaches this
+    /// many bytes, mirroring the builtin detectors' behavior.
+    pub min_bytes: usize,
+
+    /// All of these must match for the signature to fire.
+    pub patterns: Vec<PatternConfig>,
+}
+
+/// A byte pattern that must appear at a fixed `offset` within the buffer.
+/// `bytes` is a hex string (whitespace is ignored), e.g. `"16 03"`.
+#[derive(Debug, Deserialize)]
+pub struct PatternConfig {
+    #[serde(default)]
+    pub offset: usize,
+
+    pub bytes: String,
+}
+
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    InvalidPattern(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Io(ref e) => write!(f, "I/O error: {}", e),
+            ConfigError::Parse(ref e) => write!(f, "parse error: {}", e),
+            ConfigError::InvalidPattern(ref s) => write!(f, "invalid pattern: {}", s),
+        }
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> ConfigError {
+        ConfigError::Io(e)
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(e: toml::de::Error) -> ConfigError {
+        ConfigError::Parse(e)
+    }
+}
+
+
+/// Loads and parses a TOML config file from `path`.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<FileConfig, ConfigError> {
+    let mut contents = String::new();
+    let mut f = try!(File::open(path));
+    try!(f.read_to_string(&mut contents));
+
+    let cfg = try!(toml::from_str(&contents));
+    Ok(cfg)
+}
+
+/// Compiles a single signature into a runtime `Protocol`.
+pub fn compile_signature(sig: &SignatureConfig) -> Result<Protocol, ConfigError> {
+    let min_bytes = sig.min_bytes;
+
+    let mut patterns = Vec::with_capacity(sig.patterns.len());
+    for pattern in sig.patterns.iter() {
+        let bytes = try!(decode_hex(&pattern.bytes));
+        patterns.push((pattern.offset, bytes));
+    }
+
+    let detect: DetectFn = Box::new(move |buf: &[u8]| -> DetectResult {
+        if buf.len() < min_bytes {
+            return DetectResult::NotEnoughData;
+        }
+
+        for &(offset, ref want) in patterns.iter() {
+            if buf.len() < offset + want.len() {
+                return DetectResult::NotEnoughData;
+            }
+
+            if &buf[offset..offset + want.len()] != &want[..] {
+                return DetectResult::Failure;
+            }
+        }
+
+        DetectResult::Success
+    });
+
+    Ok(Protocol { name: sig.name.clone(), detect: detect })
+}
+
+fn decode_hex(s: &str) -> Result<Vec<u8>, ConfigError> {
+    let cleaned: String = s.chars().filter(|c| !c.is_whitespace()).collect();
+    if cleaned.len() % 2 != 0 {
+        return Err(ConfigError::InvalidPattern(s.to_string()));
+    }
+
+    let bytes = cleaned.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len() / 2);
+    let mut i = 0;
+    while i < bytes.len() {
+        let hi = try!(hex_digit(bytes[i]).ok_or_else(|| ConfigError::InvalidPattern(s.to_string())));
+        let lo = try!(hex_digit(bytes[i + 1]).ok_or_else(|| ConfigError::InvalidPattern(s.to_string())));
+        out.push((hi << 4) | lo);
+        i += 2;
+    }
+
+    Ok(out)
+}
+
+fn hex_digit(b: u8) -> Option<u8> {
+    match b {
+        b'0'...b'9' => Some(b - b'0'),
+        b'a'...b'f' => Some(b - b'a' + 10),
+        b'A'...b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+
+#[test]
+fn test_decode_hex() {
+    assert_eq!(decode_hex("1603").unwrap(), vec![0x16, 0x03]);
+    assert_eq!(decode_hex("16 03 02").unwrap(), vec![0x16, 0x03, 0x02]);
+    assert!(decode_hex("abc").is_err());
+    assert!(decode_hex("zz").is_err());
+}
+
+#[test]
+fn test_compile_signature_matches_at_offset() {
+    let sig = SignatureConfig {
+        name: "my-proto".to_string(),
+        min_bytes: 4,
+        patterns: vec![
+            PatternConfig { offset: 2, bytes: "cafe".to_string() },
+        ],
+    };
+
+    let protocol = compile_signature(&sig).unwrap();
+    assert_eq!(protocol.name, "my-proto");
+    assert_eq!((protocol.detect)(b"\x00\x00\xca\xfe"), DetectResult::Success);
+    assert_eq!((protocol.detect)(b"\x00\x00\xca\xff"), DetectResult::Failure);
+    assert_eq!((protocol.detect)(b"\x00\x00"), DetectResult::NotEnoughData);
+}
+
+#[test]
+fn test_parse_file_config() {
+    let toml_str = r#"
+        timeout = 2000
+        listen = ["127.0.0.1:9000"]
+
+        [upstreams]
+        http = "127.0.0.1:8080"
+
+        [sni_upstreams]
+        "example.com" = "127.0.0.1:8443"
+
+        [[signatures]]
+        name = "my-proto"
+        min_bytes = 2
+        patterns = [{ offset = 0, bytes = "cafe" }]
+    "#;
+
+    let cfg: FileConfig = toml::from_str(toml_str).unwrap();
+    assert_eq!(cfg.timeout, Some(2000));
+    assert_eq!(cfg.listen, vec!["127.0.0.1:9000".to_string()]);
+    assert_eq!(cfg.upstreams.get("http"), Some(&"127.0.0.1:8080".to_string()));
+    assert_eq!(cfg.sni_upstreams.get("example.com"), Some(&"127.0.0.1:8443".to_string()));
+    assert_eq!(cfg.signatures.len(), 1);
+    assert_eq!(cfg.signatures[0].name, "my-proto");
+}
@@ -1,18 +1,36 @@
 #[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
-enum DetectResult {
+pub enum DetectResult {
     Success,
     Failure,
     NotEnoughData,
 }
 
-type DetectFn = fn(&[u8]) -> DetectResult;
+/// A boxed detector closure. Builtin protocols wrap a plain `fn`; signatures
+/// loaded from a config file (see the `config` module) wrap a closure that
+/// closes over its compiled byte patterns. Must be `Send + Sync` since it's
+/// carried inside `Config`, which is shared across mioco coroutines.
+pub type DetectFn = Box<Fn(&[u8]) -> DetectResult + Send + Sync>;
 
-const PROTOCOLS: &'static [(&'static str, DetectFn)] = &[
-    ("tls", detect_is_tls),
-    ("http", detect_is_http),
-    ("ssh", detect_is_ssh),
-    ("xmpp", detect_is_xmpp),
-];
+/// A single protocol: a name to report/route on, plus the detector used to
+/// recognize it.
+pub struct Protocol {
+    pub name: String,
+    pub detect: DetectFn,
+}
+
+const BUILTIN_PROTOCOL_NAMES: &'static [&'static str] = &["tls", "http", "ssh", "xmpp"];
+
+/// Builds the set of protocols demuxrs recognizes out of the box. Callers
+/// (typically `main`) append any config-file-defined signatures to the
+/// returned `Vec` before passing it to `detect()`.
+pub fn builtin_protocols() -> Vec<Protocol> {
+    vec![
+        Protocol { name: "tls".to_string(), detect: Box::new(detect_is_tls) },
+        Protocol { name: "http".to_string(), detect: Box::new(detect_is_http) },
+        Protocol { name: "ssh".to_string(), detect: Box::new(detect_is_ssh) },
+        Protocol { name: "xmpp".to_string(), detect: Box::new(detect_is_xmpp) },
+    ]
+}
 
 
 fn detect_is_tls(buf: &[u8]) -> DetectResult {
@@ -88,20 +106,218 @@ fn detect_is_xmpp(buf: &[u8]) -> DetectResult {
     }
 }
 
-pub fn detect(buf: &[u8]) -> Option<&'static str> {
-    for &(name, ff) in PROTOCOLS.iter() {
-        if ff(buf) == DetectResult::Success {
-            return Some(name);
+/// Outcome of parsing a TLS ClientHello for the `server_name` (SNI)
+/// extension.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum SniResult {
+    Found(String),
+    NotFound,
+    NotEnoughData,
+}
+
+/// Parses the TLS record in `buf` (as matched by `detect_is_tls`) as a
+/// ClientHello and extracts the hostname from its `server_name` extension,
+/// if any. Returns `NotEnoughData` whenever the record or handshake length
+/// would run past the end of `buf`, so callers can retry once more bytes
+/// have arrived. A length inside the (complete) handshake that overruns it
+/// marks a malformed ClientHello and gives `NotFound`.
+pub fn detect_tls_sni(buf: &[u8]) -> SniResult {
+    // Record header: content type (0x16), version (0x03 <ver>), then a
+    // 2-byte record length.
+    if buf.len() < 5 {
+        return SniResult::NotEnoughData;
+    }
+    let record_len = ((buf[3] as usize) << 8) | (buf[4] as usize);
+    let record_end = 5 + record_len;
+    if buf.len() < record_end {
+        return SniResult::NotEnoughData;
+    }
+    let hs = &buf[5..record_end];
+
+    // Handshake header: type (0x01 == ClientHello), then a 3-byte length.
+    if hs.len() < 4 {
+        return SniResult::NotEnoughData;
+    }
+    if hs[0] != 0x01 {
+        return SniResult::NotFound;
+    }
+    let hs_len = ((hs[1] as usize) << 16) | ((hs[2] as usize) << 8) | (hs[3] as usize);
+    if hs.len() < 4 + hs_len {
+        return SniResult::NotEnoughData;
+    }
+    let body = &hs[4..4 + hs_len];
+    let mut pos = 0usize;
+
+    // Client version (2 bytes) + random (32 bytes).
+    if body.len() < pos + 34 {
+        return SniResult::NotFound;
+    }
+    pos += 34;
+
+    // Session ID.
+    if body.len() < pos + 1 {
+        return SniResult::NotFound;
+    }
+    let session_id_len = body[pos] as usize;
+    pos += 1;
+    if body.len() < pos + session_id_len {
+        return SniResult::NotFound;
+    }
+    pos += session_id_len;
+
+    // Cipher suites.
+    if body.len() < pos + 2 {
+        return SniResult::NotFound;
+    }
+    let cipher_suites_len = ((body[pos] as usize) << 8) | (body[pos + 1] as usize);
+    pos += 2;
+    if body.len() < pos + cipher_suites_len {
+        return SniResult::NotFound;
+    }
+    pos += cipher_suites_len;
+
+    // Compression methods.
+    if body.len() < pos + 1 {
+        return SniResult::NotFound;
+    }
+    let compression_methods_len = body[pos] as usize;
+    pos += 1;
+    if body.len() < pos + compression_methods_len {
+        return SniResult::NotFound;
+    }
+    pos += compression_methods_len;
+
+    // Extensions.
+    if body.len() < pos + 2 {
+        // No extensions present; a legal (if old) ClientHello.
+        return SniResult::NotFound;
+    }
+    let extensions_len = ((body[pos] as usize) << 8) | (body[pos + 1] as usize);
+    pos += 2;
+    if body.len() < pos + extensions_len {
+        return SniResult::NotFound;
+    }
+    let extensions = &body[pos..pos + extensions_len];
+
+    let mut epos = 0usize;
+    while epos + 4 <= extensions.len() {
+        let ext_type = ((extensions[epos] as usize) << 8) | (extensions[epos + 1] as usize);
+        let ext_len = ((extensions[epos + 2] as usize) << 8) | (extensions[epos + 3] as usize);
+        epos += 4;
+
+        if extensions.len() < epos + ext_len {
+            return SniResult::NotFound;
+        }
+
+        // server_name
+        if ext_type == 0x0000 {
+            let ext_body = &extensions[epos..epos + ext_len];
+            if ext_body.len() < 2 {
+                return SniResult::NotFound;
+            }
+
+            let mut lpos = 2;
+            while lpos + 3 <= ext_body.len() {
+                let name_type = ext_body[lpos];
+                let name_len = ((ext_body[lpos + 1] as usize) << 8) | (ext_body[lpos + 2] as usize);
+                lpos += 3;
+
+                if ext_body.len() < lpos + name_len {
+                    return SniResult::NotFound;
+                }
+
+                if name_type == 0x00 {
+                    return match String::from_utf8(ext_body[lpos..lpos + name_len].to_vec()) {
+                        Ok(host) => SniResult::Found(host),
+                        Err(_) => SniResult::NotFound,
+                    };
+                }
+
+                lpos += name_len;
+            }
+
+            return SniResult::NotFound;
+        }
+
+        epos += ext_len;
+    }
+
+    SniResult::NotFound
+}
+
+/// Runs every detector in `protocols` against `buf` in order, returning the
+/// name of the first one that matches.
+pub fn detect(protocols: &[Protocol], buf: &[u8]) -> Option<String> {
+    for p in protocols.iter() {
+        if (p.detect)(buf) == DetectResult::Success {
+            return Some(p.name.clone());
         }
     }
 
     None
 }
 
+/// Names of the builtin protocols, used to generate CLI flags before any
+/// config file (and its custom signatures) has been loaded.
 pub fn protocol_names() -> Vec<&'static str> {
-    PROTOCOLS.iter()
-        .map(|&(name, _)| name)
-        .collect::<Vec<_>>()
+    BUILTIN_PROTOCOL_NAMES.to_vec()
+}
+
+
+const BUILTIN_UDP_PROTOCOL_NAMES: &'static [&'static str] = &["dtls", "quic"];
+
+/// Builds the set of UDP protocols demuxrs recognizes out of the box.
+/// Unlike the TCP detectors, a whole datagram is always available at once,
+/// but detectors still report `NotEnoughData` for a too-short datagram
+/// rather than guessing.
+pub fn builtin_udp_protocols() -> Vec<Protocol> {
+    vec![
+        Protocol { name: "dtls".to_string(), detect: Box::new(detect_is_dtls) },
+        Protocol { name: "quic".to_string(), detect: Box::new(detect_is_quic) },
+    ]
+}
+
+/// Names of the builtin UDP protocols, used to generate `--<proto>-udp-upstream`
+/// CLI flags.
+pub fn udp_protocol_names() -> Vec<&'static str> {
+    BUILTIN_UDP_PROTOCOL_NAMES.to_vec()
+}
+
+fn detect_is_dtls(buf: &[u8]) -> DetectResult {
+    if buf.len() < 3 {
+        return DetectResult::NotEnoughData;
+    }
+
+    // DTLS record: content type, then version. 0xFEFD is DTLS 1.2, 0xFEFF
+    // is DTLS 1.0.
+    let content_type_ok = match buf[0] {
+        0x14...0x17 => true,
+        _ => false,
+    };
+
+    if content_type_ok && buf[1] == 0xFE && (buf[2] == 0xFD || buf[2] == 0xFF) {
+        DetectResult::Success
+    } else {
+        DetectResult::Failure
+    }
+}
+
+fn detect_is_quic(buf: &[u8]) -> DetectResult {
+    if buf.len() < 5 {
+        return DetectResult::NotEnoughData;
+    }
+
+    // A QUIC long header has the high bit of the first byte set, and is
+    // followed by a 4-byte version field that's non-zero (a zero version
+    // denotes a version negotiation packet, not an Initial packet).
+    let is_long_header = buf[0] & 0x80 != 0;
+    let version_is_nonzero = buf[1..5] != [0, 0, 0, 0];
+
+    if is_long_header && version_is_nonzero {
+        DetectResult::Success
+    } else {
+        DetectResult::Failure
+    }
 }
 
 
@@ -126,3 +342,94 @@ fn test_detect_is_tls() {
     assert_eq!(detect_is_tls(b"other data"), DetectResult::Failure);
     assert_eq!(detect_is_tls(b"aa"), DetectResult::NotEnoughData);
 }
+
+fn build_client_hello_with_sni(host: &[u8]) -> Vec<u8> {
+    let mut server_name_entry = Vec::new();
+    server_name_entry.push(0x00); // name type: host_name
+    server_name_entry.push((host.len() >> 8) as u8);
+    server_name_entry.push((host.len() & 0xff) as u8);
+    server_name_entry.extend_from_slice(host);
+
+    let mut server_name_list = Vec::new();
+    server_name_list.push((server_name_entry.len() >> 8) as u8);
+    server_name_list.push((server_name_entry.len() & 0xff) as u8);
+    server_name_list.extend_from_slice(&server_name_entry);
+
+    let mut sni_ext = Vec::new();
+    sni_ext.push(0x00);
+    sni_ext.push(0x00); // extension type: server_name
+    sni_ext.push((server_name_list.len() >> 8) as u8);
+    sni_ext.push((server_name_list.len() & 0xff) as u8);
+    sni_ext.extend_from_slice(&server_name_list);
+
+    let mut body = Vec::new();
+    body.extend_from_slice(&[0x03, 0x03]); // client version
+    body.extend_from_slice(&[0u8; 32]); // random
+    body.push(0x00); // session id len
+    body.push(0x00);
+    body.push(0x02); // cipher suites len
+    body.extend_from_slice(&[0x00, 0x2f]); // one cipher suite
+    body.push(0x01); // compression methods len
+    body.push(0x00); // compression method: null
+    body.push((sni_ext.len() >> 8) as u8);
+    body.push((sni_ext.len() & 0xff) as u8);
+    body.extend_from_slice(&sni_ext);
+
+    let mut handshake = Vec::new();
+    handshake.push(0x01); // ClientHello
+    handshake.push((body.len() >> 16) as u8);
+    handshake.push((body.len() >> 8) as u8);
+    handshake.push((body.len() & 0xff) as u8);
+    handshake.extend_from_slice(&body);
+
+    let mut record = Vec::new();
+    record.extend_from_slice(&[0x16, 0x03, 0x03]);
+    record.push((handshake.len() >> 8) as u8);
+    record.push((handshake.len() & 0xff) as u8);
+    record.extend_from_slice(&handshake);
+
+    record
+}
+
+#[test]
+fn test_detect_tls_sni_found() {
+    let record = build_client_hello_with_sni(b"example.com");
+    assert_eq!(detect_tls_sni(&record), SniResult::Found("example.com".to_string()));
+}
+
+#[test]
+fn test_detect_tls_sni_not_enough_data() {
+    assert_eq!(detect_tls_sni(b"\x16\x03\x03\x00"), SniResult::NotEnoughData);
+    assert_eq!(detect_tls_sni(b"\x16\x03\x03\x00\x05\x01\x00\x00"), SniResult::NotEnoughData);
+}
+
+#[test]
+fn test_detect_with_builtin_protocols() {
+    let protocols = builtin_protocols();
+    assert_eq!(detect(&protocols, b"SSH-2.0-OpenSSH"), Some("ssh".to_string()));
+    assert_eq!(detect(&protocols, b"not a known protocol"), None);
+}
+
+#[test]
+fn test_detect_is_dtls() {
+    assert_eq!(detect_is_dtls(b"\x16\xfe\xfd\x00\x00"), DetectResult::Success);
+    assert_eq!(detect_is_dtls(b"\x17\xfe\xff\x00\x00"), DetectResult::Success);
+    assert_eq!(detect_is_dtls(b"\x16\x03\x03\x00\x00"), DetectResult::Failure);
+    assert_eq!(detect_is_dtls(b"\xaa"), DetectResult::NotEnoughData);
+}
+
+#[test]
+fn test_detect_is_quic() {
+    assert_eq!(detect_is_quic(b"\x80\x00\x00\x00\x01"), DetectResult::Success);
+    assert_eq!(detect_is_quic(b"\x80\x00\x00\x00\x00"), DetectResult::Failure);
+    assert_eq!(detect_is_quic(b"\x00\x00\x00\x00\x01"), DetectResult::Failure);
+    assert_eq!(detect_is_quic(b"\x80\x00"), DetectResult::NotEnoughData);
+}
+
+#[test]
+fn test_detect_with_builtin_udp_protocols() {
+    let protocols = builtin_udp_protocols();
+    assert_eq!(detect(&protocols, b"\x16\xfe\xfd\x00\x00"), Some("dtls".to_string()));
+    assert_eq!(detect(&protocols, b"\x80\x00\x00\x00\x01"), Some("quic".to_string()));
+    assert_eq!(detect(&protocols, b"not a known protocol"), None);
+}
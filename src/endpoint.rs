@@ -0,0 +1,121 @@
+use std::ffi::OsStr;
+use std::io;
+use std::net::SocketAddr;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::SocketAddr as UnixSocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+
+/// An address demuxrs can listen on or dial: either a regular TCP socket,
+/// or a Unix domain socket (including Linux's abstract namespace).
+#[derive(Debug, Clone)]
+pub enum Endpoint {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for Endpoint {
+    type Err = String;
+
+    /// Parses `host:port` as a TCP endpoint, or `unix:<path>` as a Unix
+    /// domain socket. A path beginning with the escape `\x00` is turned
+    /// into a path starting with a literal NUL byte, i.e. a Linux
+    /// abstract socket name.
+    fn from_str(s: &str) -> Result<Endpoint, String> {
+        if s.starts_with("unix:") {
+            Ok(Endpoint::Unix(unescape_path(&s[5..])))
+        } else {
+            SocketAddr::from_str(s)
+                .map(Endpoint::Tcp)
+                .map_err(|e| format!("{}", e))
+        }
+    }
+}
+
+fn unescape_path(s: &str) -> PathBuf {
+    if s.starts_with("\\x00") {
+        let mut bytes = vec![0u8];
+        bytes.extend_from_slice(s[4..].as_bytes());
+        PathBuf::from(OsStr::from_bytes(&bytes))
+    } else {
+        PathBuf::from(s)
+    }
+}
+
+/// Resolves a Unix domain socket path into a `std::os::unix::net::SocketAddr`
+/// suitable for `bind_addr`/`connect_addr`. `std::os::unix::net` rejects any
+/// ordinary path containing an interior NUL byte, so a path that `unescape_path`
+/// turned into a leading-NUL byte string (a Linux abstract socket name, see
+/// `FromStr` above) has to go through `SocketAddrExt::from_abstract_name`
+/// instead of the usual pathname-based construction.
+pub fn unix_socket_addr(path: &Path) -> io::Result<UnixSocketAddr> {
+    let bytes = path.as_os_str().as_bytes();
+    if bytes.first() == Some(&0) {
+        UnixSocketAddr::from_abstract_name(&bytes[1..])
+    } else {
+        UnixSocketAddr::from_pathname(path)
+    }
+}
+
+
+#[test]
+fn test_parse_tcp() {
+    match Endpoint::from_str("127.0.0.1:8080").unwrap() {
+        Endpoint::Tcp(a) => assert_eq!(a.port(), 8080),
+        Endpoint::Unix(_) => panic!("expected Endpoint::Tcp"),
+    }
+}
+
+#[test]
+fn test_parse_unix_path() {
+    match Endpoint::from_str("unix:/tmp/demux.sock").unwrap() {
+        Endpoint::Unix(p) => assert_eq!(p, PathBuf::from("/tmp/demux.sock")),
+        Endpoint::Tcp(_) => panic!("expected Endpoint::Unix"),
+    }
+}
+
+#[test]
+fn test_parse_unix_abstract() {
+    match Endpoint::from_str("unix:\\x00demux").unwrap() {
+        Endpoint::Unix(p) => {
+            let bytes = p.as_os_str().as_bytes();
+            assert_eq!(bytes[0], 0);
+            assert_eq!(&bytes[1..], b"demux");
+        },
+        Endpoint::Tcp(_) => panic!("expected Endpoint::Unix"),
+    }
+}
+
+#[test]
+fn test_parse_invalid() {
+    assert!(Endpoint::from_str("not an address").is_err());
+}
+
+#[test]
+fn test_unix_socket_addr_pathname() {
+    let addr = unix_socket_addr(Path::new("/tmp/demux.sock")).unwrap();
+    assert_eq!(addr.as_pathname(), Some(Path::new("/tmp/demux.sock")));
+}
+
+#[test]
+fn test_unix_socket_addr_abstract_binds_and_connects() {
+    use std::io::{Read, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    let path = unescape_path("\\x00demuxrs-test-endpoint-abstract");
+
+    let listener_addr = unix_socket_addr(&path).unwrap();
+    let listener = UnixListener::bind_addr(&listener_addr).unwrap();
+
+    let connect_addr = unix_socket_addr(&path).unwrap();
+    let mut client = UnixStream::connect_addr(&connect_addr).unwrap();
+
+    let (mut server, _) = listener.accept().unwrap();
+    client.write_all(b"hi").unwrap();
+
+    let mut buf = [0u8; 2];
+    server.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hi");
+}
@@ -2,62 +2,313 @@ extern crate ansi_term;
 extern crate clap;
 extern crate fern;
 #[macro_use] extern crate log;
+extern crate mio;
 #[macro_use] extern crate mioco;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
 extern crate time;
+extern crate toml;
 
 use std::collections::HashMap;
 use std::io::{self, Read, Write};
 use std::net::SocketAddr;
+use std::os::unix::net::{UnixListener as StdUnixListener, UnixStream as StdUnixStream};
+use std::path::Path;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 use clap::{App, Arg};
+use mio::{Evented, Poll, PollOpt, Ready, Token};
 use mioco::tcp::{TcpListener, TcpStream};
+use mioco::udp::UdpSocket;
+use mioco::unix::{UnixListener, UnixStream};
 
 
+mod config;
 mod detect;
+mod endpoint;
 mod logger;
+mod proxy_protocol;
+
+use endpoint::Endpoint;
+use proxy_protocol::ProxyProtocolVersion;
 
 
 const DEFAULT_LISTEN_ADDR : &'static str = "127.0.0.1:5555";
 
 
 struct Config {
-    listen_addr: SocketAddr,
-    upstreams: HashMap<&'static str, SocketAddr>,
+    listen_addrs: Vec<Endpoint>,
+    upstreams: HashMap<String, Endpoint>,
+    sni_upstreams: HashMap<String, Endpoint>,
     timeout: i64,
+    idle_timeout: i64,
+    max_connections: usize,
+    proxy_protocol: HashMap<String, ProxyProtocolVersion>,
+    protocols: Vec<detect::Protocol>,
+    udp_listen_addr: Option<SocketAddr>,
+    udp_upstreams: HashMap<String, SocketAddr>,
+    udp_protocols: Vec<detect::Protocol>,
+    max_udp_sessions: usize,
+    udp_session_idle_timeout: i64,
 }
 
 impl Config {
-    fn upstream_for(&self, proto: &'static str) -> Option<SocketAddr> {
-        self.upstreams.get(proto).and_then(|s| Some(s.clone()))
+    fn upstream_for(&self, proto: &str) -> Option<Endpoint> {
+        self.upstreams.get(proto).cloned()
+    }
+
+    /// Resolves the upstream for a TLS connection, preferring the
+    /// `sni_upstreams` entry for the SNI hostname over the generic `tls`
+    /// upstream.
+    fn upstream_for_tls(&self, sni: Option<&str>) -> Option<Endpoint> {
+        if let Some(host) = sni {
+            if let Some(addr) = self.sni_upstreams.get(host) {
+                return Some(addr.clone());
+            }
+        }
+
+        self.upstream_for("tls")
+    }
+}
+
+
+/// Lets code generic over the connection type ask for a TCP-style network
+/// address (Unix sockets don't have one) and shut the connection down in
+/// both directions, without caring which transport backs it.
+trait ConnExt {
+    fn tcp_peer_addr(&self) -> Option<SocketAddr>;
+    fn tcp_local_addr(&self) -> Option<SocketAddr>;
+    fn shutdown_both(&self) -> io::Result<()>;
+}
+
+impl ConnExt for TcpStream {
+    fn tcp_peer_addr(&self) -> Option<SocketAddr> { self.peer_addr().ok() }
+    fn tcp_local_addr(&self) -> Option<SocketAddr> { self.local_addr().ok() }
+    fn shutdown_both(&self) -> io::Result<()> { self.shutdown(mioco::tcp::Shutdown::Both) }
+}
+
+impl ConnExt for UnixStream {
+    fn tcp_peer_addr(&self) -> Option<SocketAddr> { None }
+    fn tcp_local_addr(&self) -> Option<SocketAddr> { None }
+    fn shutdown_both(&self) -> io::Result<()> { self.shutdown(mioco::unix::Shutdown::Both) }
+}
+
+
+/// The upstream half of a relayed connection: either TCP or a Unix domain
+/// socket, chosen per-connection based on the resolved `Endpoint`.
+enum ServerConn {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+/// Binds a Unix domain socket listener at `path`, which may be an ordinary
+/// filesystem path or (per `endpoint::unix_socket_addr`) a Linux abstract
+/// socket name. `UnixListener::bind` can't be used directly here: it goes
+/// through `std::os::unix::net`, which rejects any path containing an
+/// interior NUL byte, so abstract names have to be bound via their
+/// `SocketAddr` instead.
+fn bind_unix_listener(path: &Path) -> io::Result<UnixListener> {
+    let addr = try!(endpoint::unix_socket_addr(path));
+    let std_listener = try!(StdUnixListener::bind_addr(&addr));
+    UnixListener::from_listener(std_listener)
+}
+
+/// Connects to a Unix domain socket at `path`, handling abstract socket
+/// names the same way `bind_unix_listener` does.
+fn connect_unix_stream(path: &Path) -> io::Result<UnixStream> {
+    let addr = try!(endpoint::unix_socket_addr(path));
+    let std_stream = try!(StdUnixStream::connect_addr(&addr));
+    UnixStream::from_stream(std_stream)
+}
+
+fn connect_endpoint(addr: &Endpoint) -> io::Result<ServerConn> {
+    match *addr {
+        Endpoint::Tcp(ref a) => Ok(ServerConn::Tcp(try!(TcpStream::connect(a)))),
+        Endpoint::Unix(ref p) => Ok(ServerConn::Unix(try!(connect_unix_stream(p)))),
+    }
+}
+
+impl ServerConn {
+    fn shutdown_both(&self) -> io::Result<()> {
+        match *self {
+            ServerConn::Tcp(ref s) => s.shutdown(mioco::tcp::Shutdown::Both),
+            ServerConn::Unix(ref s) => s.shutdown(mioco::unix::Shutdown::Both),
+        }
+    }
+}
+
+impl Read for ServerConn {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            ServerConn::Tcp(ref mut s) => s.read(buf),
+            ServerConn::Unix(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for ServerConn {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            ServerConn::Tcp(ref mut s) => s.write(buf),
+            ServerConn::Unix(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            ServerConn::Tcp(ref mut s) => s.flush(),
+            ServerConn::Unix(ref mut s) => s.flush(),
+        }
     }
 }
 
+impl Evented for ServerConn {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        match *self {
+            ServerConn::Tcp(ref s) => s.register(poll, token, interest, opts),
+            ServerConn::Unix(ref s) => s.register(poll, token, interest, opts),
+        }
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        match *self {
+            ServerConn::Tcp(ref s) => s.reregister(poll, token, interest, opts),
+            ServerConn::Unix(ref s) => s.reregister(poll, token, interest, opts),
+        }
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        match *self {
+            ServerConn::Tcp(ref s) => s.deregister(poll),
+            ServerConn::Unix(ref s) => s.deregister(poll),
+        }
+    }
+}
+
+
+// Once throttled, accepting doesn't resume until the live connection count
+// has dropped this far below `max_connections`, so we don't flap in and out
+// of the throttled state on every single connection close.
+const LOW_WATER_SLACK: usize = 16;
+
+/// Shared backpressure state for an accept loop: tracks the number of live
+/// connections and makes callers wait to `accept()` once `max_connections`
+/// is reached, resuming only once the count falls back to a low-water mark.
+struct ConnLimiter {
+    count: Arc<AtomicUsize>,
+    max: usize,
+    low_water: usize,
+}
+
+impl ConnLimiter {
+    fn new(max: usize) -> ConnLimiter {
+        ConnLimiter {
+            count: Arc::new(AtomicUsize::new(0)),
+            max: max,
+            low_water: max.saturating_sub(LOW_WATER_SLACK),
+        }
+    }
+
+    fn clone_handle(&self) -> ConnLimiter {
+        ConnLimiter {
+            count: self.count.clone(),
+            max: self.max,
+            low_water: self.low_water,
+        }
+    }
+
+    /// Blocks, without touching the kernel accept queue, until there's room
+    /// for another connection.
+    fn wait_for_capacity(&self) {
+        if self.count.load(Ordering::SeqCst) < self.max {
+            return;
+        }
+
+        warn!("Hit max_connections limit ({}), throttling accepts", self.max);
+        loop {
+            mioco::sleep(Duration::from_millis(50));
+            if self.count.load(Ordering::SeqCst) <= self.low_water {
+                break;
+            }
+        }
+        warn!("Connection count back below low-water mark, resuming accepts");
+    }
+
+    /// Marks one more connection as in use. Returns a guard that releases
+    /// the slot when dropped — including when dropped during an unwinding
+    /// panic, so a handler panicking on e.g. a `shutdown_both().unwrap()`
+    /// can't permanently leak capacity out of `max_connections`.
+    fn note_accepted(&self) -> ConnGuard {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        ConnGuard { count: self.count.clone() }
+    }
+
+    /// Like `note_accepted`, but for call sites that can't block waiting for
+    /// capacity (e.g. a single dispatch loop that also has to keep servicing
+    /// already-admitted work): returns `None` instead of waiting if the
+    /// limit has already been reached.
+    fn try_note_accepted(&self) -> Option<ConnGuard> {
+        if self.count.load(Ordering::SeqCst) >= self.max {
+            return None;
+        }
 
-fn handle_proxy(
-    mut client_conn: TcpStream,
-    proto: &'static str,
+        Some(self.note_accepted())
+    }
+}
+
+/// Releases one `ConnLimiter` slot on drop.
+struct ConnGuard {
+    count: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnGuard {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+
+/// Connects to `addr` and relays bytes between it and `client_conn`,
+/// prepending `initial_buf` (the bytes already consumed for protocol
+/// detection) to what the upstream sees.
+fn handle_proxy<C: Read + Write + Evented + ConnExt>(
+    mut client_conn: C,
+    proto: String,
+    addr: Endpoint,
     initial_buf: &[u8],
     config: Arc<Config>
 ) -> io::Result<()> {
 
-    // If we don't have an upstream, we just skip it.
-    // TODO: fallback?
-    let addr = match config.upstream_for(proto) {
-        Some(us) => us,
-        None => {
-            warn!("No upstream for protocol '{}', dropping connection...", proto);
-            return Ok(());
-        },
-    };
-    let mut server_conn = try!(TcpStream::connect(&addr));
+    let mut server_conn = try!(connect_endpoint(&addr));
+
+    // If enabled for this protocol, prepend a PROXY protocol header so the
+    // upstream can recover the real client address instead of seeing ours.
+    // Only meaningful when the client connected over TCP, since that's the
+    // only transport with addresses to report.
+    if let Some(&version) = config.proxy_protocol.get(&proto) {
+        match (client_conn.tcp_peer_addr(), client_conn.tcp_local_addr()) {
+            (Some(src), Some(dst)) => {
+                try!(proxy_protocol::write_header(&mut server_conn, version, src, dst));
+            },
+            _ => {
+                warn!("Cannot send a PROXY protocol header for a non-TCP client connection");
+            },
+        }
+    }
 
     // Send the initial buffer (the bits we used for protocol detection).
     try!(server_conn.write_all(initial_buf));
 
     let mut buf = [0u8; 16 * 1024];
     loop {
+        // Re-armed on every iteration so that any byte transferred in either
+        // direction resets the clock; only a fully idle connection trips it.
+        let mut timer = mioco::timer::Timer::new();
+        timer.set_timeout(config.idle_timeout);
+
         select!(
             client_conn:r => {
                 let n = try!(client_conn.read(&mut buf));
@@ -78,13 +329,39 @@ fn handle_proxy(
                 trace!("copying {} bytes from server --> client", n);
                 try!(client_conn.write_all(&buf[..n]));
             },
+
+            timer:r => {
+                trace!("timing out idle proxied connection");
+                client_conn.shutdown_both().unwrap();
+                server_conn.shutdown_both().unwrap();
+                break;
+            },
         );
     }
 
     Ok(())
 }
 
-fn handle_connection(mut conn: TcpStream, config: Arc<Config>) -> io::Result<()> {
+/// Looks up the upstream for `protocol` (already resolved by the caller)
+/// and either relays the connection to it or drops it if none is
+/// configured.
+fn dispatch<C: Read + Write + Evented + ConnExt>(
+    conn: C,
+    protocol: String,
+    addr: Option<Endpoint>,
+    buf: &[u8],
+    config: Arc<Config>
+) -> io::Result<()> {
+    match addr {
+        Some(addr) => handle_proxy(conn, protocol, addr, buf, config),
+        None => {
+            warn!("No upstream for protocol '{}', dropping connection...", protocol);
+            Ok(())
+        },
+    }
+}
+
+fn handle_connection<C: Read + Write + Evented + ConnExt>(mut conn: C, config: Arc<Config>) -> io::Result<()> {
     let mut buf = [0u8; 1024];
     let mut nread = 0usize;
 
@@ -111,31 +388,220 @@ fn handle_connection(mut conn: TcpStream, config: Arc<Config>) -> io::Result<()>
             timer:r => {
                 // Timeout :-(
                 trace!("timing out connection");
-                conn.shutdown(mioco::tcp::Shutdown::Both).unwrap();
+                conn.shutdown_both().unwrap();
                 return Ok(());
             },
         );
 
         // Run detection on the portion of the buffer we have read into.
-        let protocol = match detect::detect(&buf[..nread]) {
+        let protocol = match detect::detect(&config.protocols, &buf[..nread]) {
             Some(p) => p,
             None => continue,
         };
 
+        // TLS may carry an SNI hostname a little further into the
+        // ClientHello than what detection needed; give it a chance to
+        // arrive before picking an upstream.
+        if protocol == "tls" {
+            match detect::detect_tls_sni(&buf[..nread]) {
+                detect::SniResult::NotEnoughData => continue,
+                detect::SniResult::Found(ref host) => {
+                    debug!("Got protocol: tls (SNI: {})", host);
+                    let addr = config.upstream_for_tls(Some(host.as_str()));
+                    return dispatch(conn, protocol, addr, &buf[..nread], config);
+                },
+                detect::SniResult::NotFound => {
+                    debug!("Got protocol: tls");
+                    let addr = config.upstream_for_tls(None);
+                    return dispatch(conn, protocol, addr, &buf[..nread], config);
+                },
+            }
+        }
+
         debug!("Got protocol: {}", protocol);
-        return handle_proxy(conn, protocol, &buf[..nread], config);
+        let addr = config.upstream_for(&protocol);
+        return dispatch(conn, protocol, addr, &buf[..nread], config);
     }
 
     // Run one final detect...
-    if let Some(protocol) = detect::detect(&buf[..nread]) {
+    if let Some(protocol) = detect::detect(&config.protocols, &buf[..nread]) {
+        if protocol == "tls" {
+            let sni = detect::detect_tls_sni(&buf[..nread]);
+            let addr = match sni {
+                detect::SniResult::Found(ref host) => config.upstream_for_tls(Some(host)),
+                _ => config.upstream_for_tls(None),
+            };
+            debug!("Got protocol: {}", protocol);
+            return dispatch(conn, protocol, addr, &buf[..nread], config);
+        }
+
         debug!("Got protocol: {}", protocol);
-        handle_proxy(conn, protocol, &buf[..nread], config)
+        let addr = config.upstream_for(&protocol);
+        dispatch(conn, protocol, addr, &buf[..nread], config)
     } else {
         // TODO: default / fallback?
         Ok(())
     }
 }
 
+
+/// What we remember about a UDP client after its first datagram has been
+/// classified: the chosen upstream, and the socket dedicated to talking to
+/// it (so that its replies can be told apart from every other client's).
+struct UdpSession {
+    upstream: SocketAddr,
+    upstream_socket: UdpSocket,
+
+    /// Updated whenever a datagram crosses in either direction; read back
+    /// by the reply-relay coroutine to evict the session once it's been
+    /// idle for `udp_session_idle_timeout`.
+    last_active: Mutex<Instant>,
+
+    /// Releases this session's `max_udp_sessions` slot when the session is
+    /// dropped (i.e. once it's been evicted and removed from `sessions`),
+    /// the same backpressure mechanism `ConnLimiter` gives TCP/Unix accepts.
+    _guard: ConnGuard,
+}
+
+impl UdpSession {
+    fn touch(&self) {
+        *self.last_active.lock().unwrap() = Instant::now();
+    }
+}
+
+/// An unspecified ("any") address in the same family as `addr`, suitable
+/// for binding an outbound socket before connecting/sending to `addr`.
+fn unspecified_addr_like(addr: &SocketAddr) -> SocketAddr {
+    match *addr {
+        SocketAddr::V4(_) => SocketAddr::from_str("0.0.0.0:0").unwrap(),
+        SocketAddr::V6(_) => SocketAddr::from_str("[::]:0").unwrap(),
+    }
+}
+
+/// Runs a UDP demultiplexer on `listen_addr`: the protocol is detected from
+/// a client's first datagram and remembered per client address until that
+/// session is evicted as idle, since (unlike TCP) there's no connection to
+/// hold detection state on.
+fn run_udp_demuxer(listen_addr: SocketAddr, config: Arc<Config>) -> io::Result<()> {
+    let listen_socket = Arc::new(try!(UdpSocket::bind(&listen_addr)));
+    info!("Starting UDP demux server on {:?}", listen_socket.local_addr().unwrap());
+
+    // UDP is connectionless, so unlike the TCP/Unix accept loops there's no
+    // natural moment a session ends; bound it the same two ways accepts are
+    // bounded: admission is capped by `max_udp_sessions` (via `udp_limiter`,
+    // below), and each session's relay coroutine evicts its own entry once
+    // it's gone idle for `udp_session_idle_timeout`.
+    let sessions: Arc<Mutex<HashMap<SocketAddr, Arc<UdpSession>>>> = Arc::new(Mutex::new(HashMap::new()));
+    let udp_limiter = ConnLimiter::new(config.max_udp_sessions);
+
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let (n, from) = try!(listen_socket.recv_from(&mut buf));
+
+        let existing = sessions.lock().unwrap().get(&from).cloned();
+        let session = match existing {
+            Some(s) => s,
+            None => {
+                let protocol = match detect::detect(&config.udp_protocols, &buf[..n]) {
+                    Some(p) => p,
+                    None => {
+                        warn!("Could not detect UDP protocol from {:?}, dropping datagram", from);
+                        continue;
+                    },
+                };
+
+                let upstream = match config.udp_upstreams.get(&protocol) {
+                    Some(a) => *a,
+                    None => {
+                        warn!("No UDP upstream for protocol '{}', dropping datagram", protocol);
+                        continue;
+                    },
+                };
+
+                // Unlike the TCP/Unix accept loops, this loop also has to
+                // keep servicing datagrams for already-admitted sessions,
+                // so we can't block waiting for capacity here: just refuse
+                // the new session and let the existing ones carry on.
+                let guard = match udp_limiter.try_note_accepted() {
+                    Some(g) => g,
+                    None => {
+                        warn!("Hit max_udp_sessions limit ({}), dropping datagram from {:?}", config.max_udp_sessions, from);
+                        continue;
+                    },
+                };
+
+                debug!("Got UDP protocol '{}' from {:?}, routing to {:?}", protocol, from, upstream);
+
+                let upstream_socket = try!(UdpSocket::bind(&unspecified_addr_like(&upstream)));
+                let session = Arc::new(UdpSession {
+                    upstream: upstream,
+                    upstream_socket: upstream_socket,
+                    last_active: Mutex::new(Instant::now()),
+                    _guard: guard,
+                });
+                sessions.lock().unwrap().insert(from, session.clone());
+
+                // Relay datagrams coming back from the upstream to the
+                // client that originated this session, evicting the session
+                // once it's been idle (in either direction) for
+                // `udp_session_idle_timeout`.
+                let listen_socket = listen_socket.clone();
+                let sessions = sessions.clone();
+                let config = config.clone();
+                let session = session.clone();
+                mioco::spawn(move || -> io::Result<()> {
+                    let mut buf = [0u8; 64 * 1024];
+                    loop {
+                        // Re-armed every iteration so a reply from the
+                        // upstream resets the clock too; only a session idle
+                        // in both directions trips it.
+                        let mut timer = mioco::timer::Timer::new();
+                        timer.set_timeout(config.udp_session_idle_timeout);
+
+                        select!(
+                            session.upstream_socket:r => {
+                                let (n, src) = try!(session.upstream_socket.recv_from(&mut buf));
+
+                                // The session socket is bound but not
+                                // connected, so anyone who can reach its
+                                // ephemeral port could try to inject spoofed
+                                // "replies"; only relay datagrams that
+                                // actually came from the upstream we sent to.
+                                if src != session.upstream {
+                                    warn!("Dropping UDP datagram from unexpected source {:?} (expected upstream {:?})", src, session.upstream);
+                                    continue;
+                                }
+
+                                session.touch();
+                                try!(listen_socket.send_to(&buf[..n], &from));
+                            },
+
+                            timer:r => {
+                                let idle_for = session.last_active.lock().unwrap().elapsed();
+                                if idle_for < Duration::from_millis(config.udp_session_idle_timeout as u64) {
+                                    // Traffic arrived from the client side
+                                    // since we last armed the timer; keep
+                                    // the session alive and re-arm.
+                                    continue;
+                                }
+
+                                debug!("Evicting idle UDP session for {:?}", from);
+                                sessions.lock().unwrap().remove(&from);
+                                return Ok(());
+                            },
+                        );
+                    }
+                });
+
+                session
+            },
+        };
+
+        session.touch();
+        try!(session.upstream_socket.send_to(&buf[..n], &session.upstream));
+    }
+}
+
 fn main() {
     // Convert the protocols into a tuple of:
     //      (proto, argument name, help string)
@@ -148,6 +614,27 @@ fn main() {
         })
         .collect::<Vec<_>>();
 
+    // Same idea, but for the (optional) PROXY protocol header sent to each
+    // protocol's upstream.
+    let proxy_protocol_arg_names = detect::protocol_names().into_iter()
+        .map(|p| {
+            let arg_name = format!("{}-proxy-protocol", p);
+            let help = format!("Prepends a PROXY protocol header ('v1' or 'v2') to connections forwarded for '{}'", p);
+
+            (p, arg_name, help)
+        })
+        .collect::<Vec<_>>();
+
+    // Same idea again, but for the UDP upstream of each UDP protocol.
+    let udp_arg_names = detect::udp_protocol_names().into_iter()
+        .map(|p| {
+            let arg_name = format!("{}-udp-upstream", p);
+            let help = format!("Sets the UDP upstream address for the protocol '{}'", p);
+
+            (p, arg_name, help)
+        })
+        .collect::<Vec<_>>();
+
     let mut config = App::new("demuxrs")
         .version("0.0.1")
         .author("Andrew Dunham <andrew@du.nham.ca>")
@@ -159,12 +646,44 @@ fn main() {
         .arg(Arg::with_name("timeout")
              .short("t")
              .long("timeout")
+             .takes_value(true)
              .help("Timeout (in milliseconds) for reads (only before a protocol is detected)"))
+        .arg(Arg::with_name("idle-timeout")
+             .long("idle-timeout")
+             .takes_value(true)
+             .help("Idle timeout (in milliseconds) for established proxied connections (default: 600000)"))
+        .arg(Arg::with_name("max-connections")
+             .long("max-connections")
+             .takes_value(true)
+             .help("Maximum number of simultaneous connections before throttling accepts (default: 10000)"))
         .arg(Arg::with_name("listen")
              .short("l")
              .long("listen")
              .takes_value(true)
-             .help("The listen address in host:port form (default: localhost:5555)"));
+             .help("The listen address in host:port form (default: localhost:5555)"))
+        .arg(Arg::with_name("listen-udp")
+             .long("listen-udp")
+             .takes_value(true)
+             .help("Enables the UDP demuxer and sets its listen address, in host:port form"))
+        .arg(Arg::with_name("max-udp-sessions")
+             .long("max-udp-sessions")
+             .takes_value(true)
+             .help("Maximum number of tracked UDP client sessions before new ones are dropped (default: 10000)"))
+        .arg(Arg::with_name("udp-session-idle-timeout")
+             .long("udp-session-idle-timeout")
+             .takes_value(true)
+             .help("Idle timeout (in milliseconds) for UDP client sessions before they're evicted (default: 60000)"))
+        .arg(Arg::with_name("tls-sni-upstream")
+             .long("tls-sni-upstream")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .help("Routes TLS connections for a given SNI hostname to an upstream, in 'hostname=host:port' form"))
+        .arg(Arg::with_name("config")
+             .short("c")
+             .long("config")
+             .takes_value(true)
+             .help("Path to a TOML config file with additional listen addresses, upstreams and protocol signatures"));
 
     // Manually build up the arguments list for each protocol.
     for &(_, ref arg_name, ref help) in arg_names.iter() {
@@ -176,14 +695,33 @@ fn main() {
         );
     }
 
+    for &(_, ref arg_name, ref help) in proxy_protocol_arg_names.iter() {
+        config = config.arg(
+            Arg::with_name(&*arg_name)
+                .long(&*arg_name)
+                .takes_value(true)
+                .possible_values(&["v1", "v2"])
+                .help(&*help)
+        );
+    }
+
+    for &(_, ref arg_name, ref help) in udp_arg_names.iter() {
+        config = config.arg(
+            Arg::with_name(&*arg_name)
+                .long(&*arg_name)
+                .takes_value(true)
+                .help(&*help)
+        );
+    }
+
     // Actually parse
     let matches = config.get_matches();
     logger::init_logger_config(&matches);
 
     // Parse listen address.
-    let listen_addr = {
+    let listen_addr: Endpoint = {
         let s = matches.value_of("listen").unwrap_or(DEFAULT_LISTEN_ADDR);
-        match FromStr::from_str(s) {
+        match Endpoint::from_str(s) {
             Ok(a) => a,
             Err(e) => {
                 error!("Invalid listen address '{}': {}", s, e);
@@ -192,8 +730,20 @@ fn main() {
         }
     };
 
+    // Parse UDP listen address, if the UDP demuxer is enabled.
+    let udp_listen_addr: Option<SocketAddr> = match matches.value_of("listen-udp") {
+        Some(s) => match SocketAddr::from_str(s) {
+            Ok(a) => Some(a),
+            Err(e) => {
+                error!("Invalid UDP listen address '{}': {}", s, e);
+                return;
+            },
+        },
+        None => None,
+    };
+
     // Parse timeout
-    let timeout = {
+    let mut timeout = {
         let s = matches.value_of("timeout").unwrap_or("1000");
         match FromStr::from_str(s) {
             Ok(v) => v,
@@ -204,11 +754,154 @@ fn main() {
         }
     };
 
-    // Parse the upstreams into SocketAddrs.
+    // Parse idle timeout
+    let mut idle_timeout = {
+        let s = matches.value_of("idle-timeout").unwrap_or("600000");
+        match FromStr::from_str(s) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Invalid idle timeout '{}': {}", s, e);
+                return;
+            },
+        }
+    };
+
+    // Parse max connections
+    let mut max_connections = {
+        let s = matches.value_of("max-connections").unwrap_or("10000");
+        match FromStr::from_str(s) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Invalid max connections '{}': {}", s, e);
+                return;
+            },
+        }
+    };
+
+    // Parse max UDP sessions
+    let max_udp_sessions = {
+        let s = matches.value_of("max-udp-sessions").unwrap_or("10000");
+        match FromStr::from_str(s) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Invalid max UDP sessions '{}': {}", s, e);
+                return;
+            },
+        }
+    };
+
+    // Parse UDP session idle timeout
+    let udp_session_idle_timeout = {
+        let s = matches.value_of("udp-session-idle-timeout").unwrap_or("60000");
+        match FromStr::from_str(s) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Invalid UDP session idle timeout '{}': {}", s, e);
+                return;
+            },
+        }
+    };
+
+    // Load the (optional) config file, which can supply additional listen
+    // addresses, upstreams and user-defined protocol signatures.
+    let file_config = match matches.value_of("config") {
+        Some(path) => match config::load(path) {
+            Ok(c) => Some(c),
+            Err(e) => {
+                error!("Failed to load config file '{}': {}", path, e);
+                return;
+            },
+        },
+        None => None,
+    };
+
+    let mut protocols = detect::builtin_protocols();
+    let mut listen_addrs = vec![listen_addr];
+    let mut upstreams: HashMap<String, Endpoint> = HashMap::new();
+    let mut sni_upstreams: HashMap<String, Endpoint> = HashMap::new();
+
+    if let Some(ref fc) = file_config {
+        // An explicit CLI flag always wins over the config file, same as
+        // every other setting below; only fall back to the file's value
+        // when the flag wasn't passed at all.
+        if !matches.is_present("timeout") {
+            if let Some(t) = fc.timeout {
+                timeout = t;
+            }
+        }
+
+        if !matches.is_present("idle-timeout") {
+            if let Some(t) = fc.idle_timeout {
+                idle_timeout = t;
+            }
+        }
+
+        if !matches.is_present("max-connections") {
+            if let Some(n) = fc.max_connections {
+                max_connections = n;
+            }
+        }
+
+        for addr_str in fc.listen.iter() {
+            match Endpoint::from_str(addr_str) {
+                Ok(a) => listen_addrs.push(a),
+                Err(e) => {
+                    error!("Invalid listen address '{}' in config file: {}", addr_str, e);
+                    return;
+                },
+            }
+        }
+
+        for (proto, addr_str) in fc.upstreams.iter() {
+            match Endpoint::from_str(addr_str) {
+                Ok(a) => { upstreams.insert(proto.clone(), a); },
+                Err(e) => {
+                    error!("Invalid upstream address '{}' for protocol '{}' in config file: {}", addr_str, proto, e);
+                    return;
+                },
+            }
+        }
+
+        for (host, addr_str) in fc.sni_upstreams.iter() {
+            match Endpoint::from_str(addr_str) {
+                Ok(a) => { sni_upstreams.insert(host.clone(), a); },
+                Err(e) => {
+                    error!("Invalid upstream address '{}' for SNI hostname '{}' in config file: {}", addr_str, host, e);
+                    return;
+                },
+            }
+        }
+
+        for sig in fc.signatures.iter() {
+            match config::compile_signature(sig) {
+                Ok(p) => {
+                    debug!("Loaded user-defined protocol signature '{}'", p.name);
+                    protocols.push(p);
+                },
+                Err(e) => {
+                    error!("Invalid signature '{}' in config file: {}", sig.name, e);
+                    return;
+                },
+            }
+        }
+    }
+
+    // Assemble the runtime config, merging in whatever the config file
+    // supplied above.
     let mut config = Config {
-        listen_addr: listen_addr,
-        upstreams: HashMap::new(),
+        listen_addrs: listen_addrs,
+        upstreams: upstreams,
+        sni_upstreams: sni_upstreams,
         timeout: timeout,
+        idle_timeout: idle_timeout,
+        max_connections: max_connections,
+        proxy_protocol: HashMap::new(),
+        protocols: protocols,
+        udp_listen_addr: udp_listen_addr,
+        udp_upstreams: HashMap::new(),
+        udp_protocols: detect::builtin_udp_protocols(),
+        max_udp_sessions: max_udp_sessions,
+        udp_session_idle_timeout: udp_session_idle_timeout,
     };
     for &(proto, ref arg_name, _) in arg_names.iter() {
         let saddr = match matches.value_of(&*arg_name) {
@@ -216,7 +909,7 @@ fn main() {
             None => continue,
         };
 
-        let addr: SocketAddr = match FromStr::from_str(saddr) {
+        let addr = match Endpoint::from_str(saddr) {
             Ok(a) => a,
             Err(e) => {
                 error!("Invalid upstream address for protocol '{}': {}", proto, e);
@@ -224,23 +917,123 @@ fn main() {
             },
         };
 
-        debug!("Upstream address for protocol '{}': {}", proto, addr);
-        config.upstreams.insert(proto, addr);
+        debug!("Upstream address for protocol '{}': {:?}", proto, addr);
+        config.upstreams.insert(proto.to_string(), addr);
+    }
+
+    // Parse the SNI-qualified TLS upstreams ('hostname=host:port' pairs).
+    if let Some(values) = matches.values_of("tls-sni-upstream") {
+        for value in values {
+            let mut parts = value.splitn(2, '=');
+            let (host, saddr) = match (parts.next(), parts.next()) {
+                (Some(host), Some(saddr)) => (host, saddr),
+                _ => {
+                    error!("Invalid --tls-sni-upstream value '{}', expected 'hostname=host:port'", value);
+                    continue;
+                },
+            };
+
+            let addr = match Endpoint::from_str(saddr) {
+                Ok(a) => a,
+                Err(e) => {
+                    error!("Invalid upstream address for SNI hostname '{}': {}", host, e);
+                    continue;
+                },
+            };
+
+            debug!("Upstream address for TLS SNI hostname '{}': {:?}", host, addr);
+            config.sni_upstreams.insert(host.to_string(), addr);
+        }
+    }
+
+    // Parse the (optional) PROXY protocol version for each protocol's
+    // upstream. clap's `possible_values` already validated the string, so
+    // this can't fail.
+    for &(proto, ref arg_name, _) in proxy_protocol_arg_names.iter() {
+        let version = match matches.value_of(&*arg_name) {
+            Some(v) => ProxyProtocolVersion::from_str(v).unwrap(),
+            None => continue,
+        };
+
+        debug!("Sending PROXY protocol {:?} header for protocol '{}'", version, proto);
+        config.proxy_protocol.insert(proto.to_string(), version);
+    }
+
+    for &(proto, ref arg_name, _) in udp_arg_names.iter() {
+        let saddr = match matches.value_of(&*arg_name) {
+            Some(v) => v,
+            None => continue,
+        };
+
+        let addr = match SocketAddr::from_str(saddr) {
+            Ok(a) => a,
+            Err(e) => {
+                error!("Invalid UDP upstream address for protocol '{}': {}", proto, e);
+                continue;
+            },
+        };
+
+        debug!("UDP upstream address for protocol '{}': {:?}", proto, addr);
+        config.udp_upstreams.insert(proto.to_string(), addr);
     }
 
     mioco::start(move || {
         let config = Arc::new(config);
-        let listener = TcpListener::bind(&config.listen_addr).unwrap();
 
-        info!("Starting demux server on {:?}", listener.local_addr().unwrap());
+        // Shared across every listen address, since it's the total number
+        // of live connections (not a per-listener limit) that matters.
+        let limiter = ConnLimiter::new(config.max_connections);
 
-        loop {
-            let conn = try!(listener.accept());
+        // One accept loop per configured listen address (normally just the
+        // one from --listen, plus any extras from the config file).
+        for listen_addr in config.listen_addrs.clone() {
+            let c = config.clone();
+            let limiter = limiter.clone_handle();
+
+            match listen_addr {
+                Endpoint::Tcp(ref a) => {
+                    let listener = TcpListener::bind(a).unwrap();
+                    info!("Starting demux server on {:?}", listener.local_addr().unwrap());
+
+                    mioco::spawn(move || {
+                        loop {
+                            limiter.wait_for_capacity();
+                            let conn = try!(listener.accept());
+                            let guard = limiter.note_accepted();
+
+                            let c = c.clone();
+                            mioco::spawn(move || {
+                                let _guard = guard;
+                                handle_connection(conn, c)
+                            });
+                        }
+                    });
+                },
+
+                Endpoint::Unix(ref p) => {
+                    let listener = bind_unix_listener(p).unwrap();
+                    info!("Starting demux server on {:?}", p);
+
+                    mioco::spawn(move || {
+                        loop {
+                            limiter.wait_for_capacity();
+                            let conn = try!(listener.accept());
+                            let guard = limiter.note_accepted();
+
+                            let c = c.clone();
+                            mioco::spawn(move || {
+                                let _guard = guard;
+                                handle_connection(conn, c)
+                            });
+                        }
+                    });
+                },
+            }
+        }
 
+        if let Some(addr) = config.udp_listen_addr {
             let c = config.clone();
-            mioco::spawn(move || {
-                handle_connection(conn, c)
-            });
+            mioco::spawn(move || run_udp_demuxer(addr, c));
         }
     });
 }